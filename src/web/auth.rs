@@ -0,0 +1,249 @@
+//! Implements optional bearer token authentication for the serve HTTP API
+//! and UI. When no secret is configured, every request passes through
+//! unchanged; this middleware is a no-op by default so existing localhost
+//! workflows are unaffected.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::Future;
+use hyper::{header, Body, Request, Response, StatusCode};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::web::{interface::ErrorResponse, util::json};
+
+/// How long a token minted from `/api/auth` stays valid before it's treated
+/// as expired and pruned.
+const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Request body for `POST /api/auth`.
+#[derive(Debug, Deserialize)]
+pub struct AuthRequest {
+    pub secret: String,
+    pub scope: TokenScope,
+}
+
+/// Response body for `POST /api/auth`.
+#[derive(Debug, Serialize)]
+pub struct AuthResponse {
+    pub token: String,
+}
+
+/// Handles `POST /api/auth`: exchanges the configured secret for a
+/// short-lived, scoped session token.
+pub fn handle_auth_request(
+    auth: &AuthState,
+    candidate_secret: &str,
+    scope: TokenScope,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    match auth.issue_token(candidate_secret, scope) {
+        Some(token) => json(AuthResponse { token }, StatusCode::OK),
+        None => json(
+            ErrorResponse::unauthorized("Invalid secret"),
+            StatusCode::UNAUTHORIZED,
+        ),
+    }
+}
+
+/// What a session token is allowed to do. A token minted from `/api/auth`
+/// carries whichever scope the request asked for; the configured secret
+/// itself, presented directly, passes every scope check.
+///
+/// `Subscribe` is a superset of `Read`: every route that exists today only
+/// ever requires `Read`, so a `Subscribe` token needs to satisfy that check
+/// too or it would be unusable the moment it's issued. [`TokenScope::satisfies`]
+/// is where that hierarchy is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Subscribe,
+}
+
+impl TokenScope {
+    /// Returns `true` if a token issued with `self` is allowed to access a
+    /// route that requires `required`.
+    fn satisfies(self, required: TokenScope) -> bool {
+        self == required || self == TokenScope::Subscribe
+    }
+}
+
+struct IssuedToken {
+    value: String,
+    scope: TokenScope,
+    expires_at: Instant,
+}
+
+/// Holds the configured secret (if any) and any session tokens issued from
+/// it. `UiService` holds it behind an `Arc` (passed in through
+/// `UiService::new`) so its handlers can both validate incoming requests and
+/// mint new tokens; it is not stored on `ServeSession`.
+pub struct AuthState {
+    secret: Option<String>,
+    issued: Mutex<Vec<IssuedToken>>,
+}
+
+impl AuthState {
+    pub fn new(secret: Option<String>) -> Self {
+        AuthState {
+            secret,
+            issued: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Exchanges the configured secret for a freshly-minted, scoped session
+    /// token good for [`TOKEN_TTL`]. Returns `None` if auth isn't enabled or
+    /// `candidate` doesn't match the configured secret.
+    pub fn issue_token(&self, candidate: &str, scope: TokenScope) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+
+        if !constant_time_eq(secret.as_bytes(), candidate.as_bytes()) {
+            return None;
+        }
+
+        let value: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .collect();
+
+        let mut issued = self.issued.lock().unwrap();
+        prune_expired(&mut issued);
+        issued.push(IssuedToken {
+            value: value.clone(),
+            scope,
+            expires_at: Instant::now() + TOKEN_TTL,
+        });
+
+        Some(value)
+    }
+
+    /// Validates `candidate` against the configured secret and any
+    /// non-expired issued session tokens whose scope covers `required`, in
+    /// constant time.
+    pub fn validate(&self, candidate: &str, required: TokenScope) -> bool {
+        let secret_ok = self
+            .secret
+            .as_ref()
+            .map(|secret| constant_time_eq(secret.as_bytes(), candidate.as_bytes()))
+            .unwrap_or(false);
+
+        if secret_ok {
+            return true;
+        }
+
+        let mut issued = self.issued.lock().unwrap();
+        prune_expired(&mut issued);
+
+        issued.iter().any(|token| {
+            token.scope.satisfies(required)
+                && constant_time_eq(token.value.as_bytes(), candidate.as_bytes())
+        })
+    }
+}
+
+/// Drops every token past its `expires_at`, so a client that re-authenticates
+/// periodically doesn't leak memory into `issued` forever.
+fn prune_expired(issued: &mut Vec<IssuedToken>) {
+    let now = Instant::now();
+    issued.retain(|token| token.expires_at > now);
+}
+
+/// Compares two byte strings in constant time with respect to their
+/// contents, so failed comparisons can't be used to learn the secret one
+/// byte at a time via timing. Also used by [`crate::web::relay`], which
+/// guards a different kind of shared secret (a relay registration key)
+/// against the same class of attack.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+
+    diff == 0
+}
+
+fn extract_bearer_token(request: &Request<Body>) -> Option<&str> {
+    let header = request.headers().get(header::AUTHORIZATION)?;
+    let value = header.to_str().ok()?;
+    value.strip_prefix("Bearer ")
+}
+
+/// Checks whether `request` carries a bearer token valid for `required`.
+/// Always `true` when `auth` has no secret configured, so callers can gate
+/// a route with this without special-casing the disabled case themselves.
+pub fn authorized(auth: &AuthState, request: &Request<Body>, required: TokenScope) -> bool {
+    if !auth.enabled() {
+        return true;
+    }
+
+    extract_bearer_token(request)
+        .map(|token| auth.validate(token, required))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_matches_equal_strings() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_strings() {
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_lengths() {
+        assert!(!constant_time_eq(b"hunter2", b"hunter22"));
+        assert!(!constant_time_eq(b"hunter22", b"hunter2"));
+    }
+
+    #[test]
+    fn constant_time_eq_treats_empty_strings_as_equal() {
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn subscribe_scope_satisfies_read_but_read_does_not_satisfy_subscribe() {
+        assert!(TokenScope::Subscribe.satisfies(TokenScope::Read));
+        assert!(TokenScope::Subscribe.satisfies(TokenScope::Subscribe));
+        assert!(TokenScope::Read.satisfies(TokenScope::Read));
+        assert!(!TokenScope::Read.satisfies(TokenScope::Subscribe));
+    }
+
+    #[test]
+    fn validate_accepts_a_subscribe_scoped_token_against_a_read_requirement() {
+        let auth = AuthState::new(Some("sekret".to_owned()));
+        let token = auth
+            .issue_token("sekret", TokenScope::Subscribe)
+            .expect("secret should be accepted");
+
+        assert!(auth.validate(&token, TokenScope::Read));
+        assert!(auth.validate(&token, TokenScope::Subscribe));
+    }
+
+    #[test]
+    fn validate_rejects_a_read_scoped_token_against_a_subscribe_requirement() {
+        let auth = AuthState::new(Some("sekret".to_owned()));
+        let token = auth
+            .issue_token("sekret", TokenScope::Read)
+            .expect("secret should be accepted");
+
+        assert!(auth.validate(&token, TokenScope::Read));
+        assert!(!auth.validate(&token, TokenScope::Subscribe));
+    }
+}
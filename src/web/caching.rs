@@ -0,0 +1,222 @@
+//! Shared helpers for conditional requests (`If-None-Match` /
+//! `If-Modified-Since`) and `Cache-Control` headers, used by both the
+//! static assets served out of `UiService` and the API's read responses.
+//!
+//! `If-Modified-Since` is only wired up for [`immutable_asset_response`]:
+//! the compile-time-constant assets it serves have a real, stable "last
+//! modified" instant (when this process started), but instances read
+//! through [`revalidated_response`] have no modification timestamp
+//! tracked anywhere in this tree, so that helper stays `ETag`-only rather
+//! than comparing a fabricated one.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Once;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+
+/// Hashes `bytes` into a quoted ETag that is the same for the same content.
+/// Used for the compile-time-constant assets (the logo, icon, and
+/// stylesheet) and for `/api/read` bodies; the hash is computed afresh on
+/// every call.
+pub fn etag_for_bytes(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+/// Returns `true` if `headers` carries an `If-None-Match` that matches
+/// `etag`, meaning the client's cached copy is still good.
+pub fn matches_if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').any(|candidate| candidate.trim() == etag))
+        .unwrap_or(false)
+}
+
+/// Returns `true` if `headers` carries an `If-Modified-Since` at or after
+/// `last_modified`, meaning the client's cached copy is still good. HTTP
+/// dates only have one-second resolution, so `last_modified` is truncated
+/// to the second before comparing, the same as the header it's compared
+/// against.
+pub fn matches_if_modified_since(headers: &HeaderMap, last_modified: SystemTime) -> bool {
+    let last_modified_secs = last_modified
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let last_modified = UNIX_EPOCH + Duration::from_secs(last_modified_secs);
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+        .map(|since| last_modified <= since)
+        .unwrap_or(false)
+}
+
+/// The instant this function was first called (the first asset request
+/// after the process started), used as the `Last-Modified` time for the
+/// compile-time-constant assets served by [`immutable_asset_response`]:
+/// their contents can't change without a restart, so that instant is an
+/// honest (if conservative) modification time.
+fn process_start_time() -> SystemTime {
+    static INIT: Once = Once::new();
+    static mut START: Option<SystemTime> = None;
+
+    unsafe {
+        INIT.call_once(|| START = Some(SystemTime::now()));
+        START.unwrap()
+    }
+}
+
+/// Builds a response for an immutable, compile-time-constant asset (the
+/// logo, icon, or stylesheet): a `304 Not Modified` if the request's
+/// `If-None-Match` or `If-Modified-Since` already shows the client's
+/// cached copy is current, otherwise the full body with a stable `ETag`,
+/// `Last-Modified`, and a long-lived, `immutable` `Cache-Control`.
+pub fn immutable_asset_response(
+    request: &Request<Body>,
+    content_type: &'static str,
+    bytes: &'static [u8],
+) -> Response<Body> {
+    let etag = etag_for_bytes(bytes);
+    let last_modified = process_start_time();
+    let last_modified_header = httpdate::fmt_http_date(last_modified);
+
+    if matches_if_none_match(request.headers(), &etag)
+        || matches_if_modified_since(request.headers(), last_modified)
+    {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .header(header::LAST_MODIFIED, last_modified_header)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ETAG, etag)
+        .header(header::LAST_MODIFIED, last_modified_header)
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+        .body(Body::from(bytes))
+        .unwrap()
+}
+
+/// Builds a response for revalidatable API data (like `/api/read`): a `304
+/// Not Modified` if the request's `If-None-Match` already matches
+/// `etag`, otherwise the full body with that `ETag` and a `no-cache`
+/// `Cache-Control` that forces the client to revalidate on every use
+/// instead of trusting a local copy blindly.
+pub fn revalidated_response(
+    request: &Request<Body>,
+    content_type: &'static str,
+    etag: &str,
+    body: Vec<u8>,
+) -> Response<Body> {
+    if matches_if_none_match(request.headers(), etag) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn etag_for_bytes_is_stable_and_content_dependent() {
+        assert_eq!(etag_for_bytes(b"hello"), etag_for_bytes(b"hello"));
+        assert_ne!(etag_for_bytes(b"hello"), etag_for_bytes(b"goodbye"));
+    }
+
+    #[test]
+    fn matches_if_none_match_accepts_an_exact_match() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, "\"abc123\"".parse().unwrap());
+
+        assert!(matches_if_none_match(&headers, "\"abc123\""));
+    }
+
+    #[test]
+    fn matches_if_none_match_accepts_any_entry_in_a_list() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            "\"one\", \"two\", \"three\"".parse().unwrap(),
+        );
+
+        assert!(matches_if_none_match(&headers, "\"two\""));
+    }
+
+    #[test]
+    fn matches_if_none_match_rejects_a_stale_etag() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, "\"abc123\"".parse().unwrap());
+
+        assert!(!matches_if_none_match(&headers, "\"def456\""));
+    }
+
+    #[test]
+    fn matches_if_none_match_rejects_a_missing_header() {
+        let headers = HeaderMap::new();
+
+        assert!(!matches_if_none_match(&headers, "\"abc123\""));
+    }
+
+    #[test]
+    fn matches_if_modified_since_accepts_a_timestamp_at_or_after_last_modified() {
+        let last_modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            httpdate::fmt_http_date(last_modified).parse().unwrap(),
+        );
+        assert!(matches_if_modified_since(&headers, last_modified));
+
+        let mut later_headers = HeaderMap::new();
+        later_headers.insert(
+            header::IF_MODIFIED_SINCE,
+            httpdate::fmt_http_date(last_modified + std::time::Duration::from_secs(60))
+                .parse()
+                .unwrap(),
+        );
+        assert!(matches_if_modified_since(&later_headers, last_modified));
+    }
+
+    #[test]
+    fn matches_if_modified_since_rejects_a_timestamp_before_last_modified() {
+        let last_modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            httpdate::fmt_http_date(last_modified - std::time::Duration::from_secs(60))
+                .parse()
+                .unwrap(),
+        );
+
+        assert!(!matches_if_modified_since(&headers, last_modified));
+    }
+
+    #[test]
+    fn matches_if_modified_since_rejects_a_missing_header() {
+        let headers = HeaderMap::new();
+
+        assert!(!matches_if_modified_since(&headers, SystemTime::now()));
+    }
+}
@@ -0,0 +1,165 @@
+//! Implements `GET /api/export`, which returns the project's in-memory
+//! filesystem (or a subtree selected by `?id=<RbxId>`) as a ZIP archive.
+//! Unlike `/api/read`, which returns one instance at a time, this endpoint
+//! lets a client snapshot or hand off the whole live-synced project state
+//! in one request.
+
+use std::io::{self, Cursor, Write};
+
+use futures::{sync::mpsc, Future, Sink, Stream};
+use hyper::{header, Body, Response, StatusCode};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    imfs::{Imfs, ImfsEntry, ImfsFetcher},
+    web::{interface::ErrorResponse, util::json},
+};
+
+/// Size of the chunks the finished archive is split into before being handed
+/// to `hyper::Body`, so the response is written to the socket incrementally
+/// instead of in one giant write.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Builds a `GET /api/export` response, zipping up `entry` and everything
+/// below it on a background thread.
+///
+/// The `zip` crate needs a `Write + Seek` destination (it comes back and
+/// patches local file headers once it knows each entry's size), so the
+/// archive has to be assembled into a seekable in-memory buffer rather than
+/// written directly into the response body as it's produced. Once the
+/// background thread finishes, the finished bytes are split into
+/// [`STREAM_CHUNK_SIZE`] chunks and sent over the same channel a true
+/// streaming writer would have used, so the response body is still filled
+/// in incrementally rather than as one `Body::from(Vec<u8>)`.
+pub fn handle_export<F: ImfsFetcher>(
+    imfs: &mut Imfs<F>,
+    entry: &ImfsEntry,
+    project_name: &str,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let entries = match collect_entries(imfs, entry) {
+        Ok(entries) => entries,
+        Err(err) => {
+            return json(
+                ErrorResponse::internal_error(format!("Could not read project files: {}", err)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(8);
+
+    std::thread::spawn(move || {
+        let bytes = match build_zip(entries) {
+            Ok(bytes) => bytes,
+            Err(_) => return,
+        };
+
+        let mut tx = tx;
+        for chunk in bytes.chunks(STREAM_CHUNK_SIZE) {
+            match tx.send(chunk.to_vec()).wait() {
+                Ok(next_tx) => tx = next_tx,
+                Err(_) => break,
+            }
+        }
+    });
+
+    let body =
+        Body::wrap_stream(rx.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "export channel closed unexpectedly")
+        }));
+
+    let filename = format!("{}.zip", project_name);
+
+    Box::new(futures::future::ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/zip")
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            )
+            .body(body)
+            .unwrap(),
+    ))
+}
+
+/// Writes `entries` out as a ZIP archive into an in-memory buffer, which is
+/// what `ZipWriter`'s `Write + Seek` bound requires.
+fn build_zip(entries: Vec<(String, Vec<u8>)>) -> io::Result<Vec<u8>> {
+    let mut cursor = Cursor::new(Vec::new());
+
+    {
+        let mut zip = ZipWriter::new(&mut cursor);
+        let options = FileOptions::default();
+
+        for (path, contents) in entries {
+            zip.start_file(path, options)?;
+            zip.write_all(&contents)?;
+        }
+
+        zip.finish()?;
+    }
+
+    Ok(cursor.into_inner())
+}
+
+/// Walks `entry` and every descendant, reading file contents eagerly so the
+/// background thread doing the zipping doesn't need to touch the `Imfs`
+/// (and the lock it holds) while it runs.
+fn collect_entries<F: ImfsFetcher>(
+    imfs: &mut Imfs<F>,
+    entry: &ImfsEntry,
+) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut out = Vec::new();
+    collect_entries_into(imfs, entry, &mut out)?;
+    Ok(out)
+}
+
+fn collect_entries_into<F: ImfsFetcher>(
+    imfs: &mut Imfs<F>,
+    entry: &ImfsEntry,
+    out: &mut Vec<(String, Vec<u8>)>,
+) -> io::Result<()> {
+    if entry.is_directory() {
+        for child in entry.children(imfs)? {
+            collect_entries_into(imfs, &child, out)?;
+        }
+    } else {
+        let contents = entry.contents(imfs)?;
+        out.push((entry.path().to_string_lossy().into_owned(), contents));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_zip_round_trips_entries() {
+        let entries = vec![
+            ("foo.txt".to_owned(), b"hello".to_vec()),
+            ("bar/baz.txt".to_owned(), b"world".to_vec()),
+        ];
+
+        let bytes = build_zip(entries).expect("build_zip should succeed");
+
+        let mut archive =
+            zip::ZipArchive::new(Cursor::new(bytes)).expect("output should be a valid zip");
+
+        assert_eq!(archive.len(), 2);
+
+        let mut foo = archive.by_name("foo.txt").unwrap();
+        let mut foo_contents = Vec::new();
+        io::Read::read_to_end(&mut foo, &mut foo_contents).unwrap();
+        assert_eq!(foo_contents, b"hello");
+    }
+
+    #[test]
+    fn build_zip_handles_no_entries() {
+        let bytes = build_zip(Vec::new()).expect("build_zip should succeed with no entries");
+        let archive =
+            zip::ZipArchive::new(Cursor::new(bytes)).expect("output should be a valid zip");
+        assert_eq!(archive.len(), 0);
+    }
+}
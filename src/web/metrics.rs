@@ -0,0 +1,236 @@
+//! Implements a `GET /metrics` endpoint in Prometheus text exposition
+//! format, plus the [`Registry`] that [`crate::web::ui::UiService`] records
+//! per-route request counts and latencies into as it handles each request.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use hyper::{header, Body, Response};
+
+/// Upper bounds (in seconds) of the fixed latency buckets every route's
+/// histogram is tracked in. Borrowed from Prometheus's own client library
+/// defaults, which comfortably span a static-asset response (milliseconds)
+/// through a slow `/api/export` of a large project (seconds).
+const HISTOGRAM_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Shared counters and histograms for one `rojo serve` instance. Cheap to
+/// clone; every clone points at the same underlying state.
+#[derive(Clone, Default)]
+pub struct Registry {
+    inner: Arc<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    requests_total: Mutex<HashMap<(String, u16), u64>>,
+    request_duration_seconds: Mutex<HashMap<String, RouteHistogram>>,
+    subscribe_clients: AtomicU64,
+    instance_count: AtomicU64,
+    imfs_entry_count: AtomicU64,
+}
+
+/// A request-latency histogram for one route, bucketed into
+/// [`HISTOGRAM_BUCKETS`] as samples come in, rather than keeping every raw
+/// sample around forever.
+struct RouteHistogram {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl RouteHistogram {
+    fn new() -> Self {
+        RouteHistogram {
+            bucket_counts: vec![0; HISTOGRAM_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Records `value` into every bucket whose upper bound it falls within,
+    /// Prometheus-style, so each bucket count is already the cumulative
+    /// count of samples `<= le` with no extra summing at render time.
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket_count) in HISTOGRAM_BUCKETS.iter().zip(self.bucket_counts.iter_mut()) {
+            if value <= *bound {
+                *bucket_count += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a request to `route` completed with `status`, having
+    /// taken `duration_seconds` seconds to do so.
+    pub fn observe_request(&self, route: &str, status: u16, duration_seconds: f64) {
+        let mut totals = self.inner.requests_total.lock().unwrap();
+        *totals.entry((route.to_owned(), status)).or_insert(0) += 1;
+        drop(totals);
+
+        let mut histograms = self.inner.request_duration_seconds.lock().unwrap();
+        histograms
+            .entry(route.to_owned())
+            .or_insert_with(RouteHistogram::new)
+            .observe(duration_seconds);
+    }
+
+    /// Tracks a connected `/api/subscribe` long-poll client.
+    ///
+    /// Nothing in this tree currently implements `/api/subscribe`, so this
+    /// gauge stays at zero until that endpoint exists; it's wired up ahead
+    /// of time so that endpoint only has to call it, not add it.
+    pub fn subscribe_client_connected(&self) {
+        self.inner.subscribe_clients.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn subscribe_client_disconnected(&self) {
+        self.inner.subscribe_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn set_instance_count(&self, count: u64) {
+        self.inner.instance_count.store(count, Ordering::SeqCst);
+    }
+
+    pub fn set_imfs_entry_count(&self, count: u64) {
+        self.inner.imfs_entry_count.store(count, Ordering::SeqCst);
+    }
+
+    /// Renders all tracked metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP rojo_http_requests_total Total number of HTTP requests.\n");
+        output.push_str("# TYPE rojo_http_requests_total counter\n");
+        for ((route, status), count) in self.inner.requests_total.lock().unwrap().iter() {
+            output.push_str(&format!(
+                "rojo_http_requests_total{{route=\"{}\",status=\"{}\"}} {}\n",
+                route, status, count
+            ));
+        }
+
+        output.push_str(
+            "# HELP rojo_http_request_duration_seconds HTTP request latency in seconds.\n",
+        );
+        output.push_str("# TYPE rojo_http_request_duration_seconds histogram\n");
+        for (route, histogram) in self.inner.request_duration_seconds.lock().unwrap().iter() {
+            for (bound, bucket_count) in
+                HISTOGRAM_BUCKETS.iter().zip(histogram.bucket_counts.iter())
+            {
+                output.push_str(&format!(
+                    "rojo_http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}\n",
+                    route, bound, bucket_count
+                ));
+            }
+            output.push_str(&format!(
+                "rojo_http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}\n",
+                route, histogram.count
+            ));
+            output.push_str(&format!(
+                "rojo_http_request_duration_seconds_sum{{route=\"{}\"}} {}\n",
+                route, histogram.sum
+            ));
+            output.push_str(&format!(
+                "rojo_http_request_duration_seconds_count{{route=\"{}\"}} {}\n",
+                route, histogram.count
+            ));
+        }
+
+        output.push_str(
+            "# HELP rojo_subscribe_clients Number of currently-connected /api/subscribe long-poll clients.\n",
+        );
+        output.push_str("# TYPE rojo_subscribe_clients gauge\n");
+        output.push_str(&format!(
+            "rojo_subscribe_clients {}\n",
+            self.inner.subscribe_clients.load(Ordering::SeqCst)
+        ));
+
+        output.push_str("# HELP rojo_instance_count Number of instances in the instance tree.\n");
+        output.push_str("# TYPE rojo_instance_count gauge\n");
+        output.push_str(&format!(
+            "rojo_instance_count {}\n",
+            self.inner.instance_count.load(Ordering::SeqCst)
+        ));
+
+        output.push_str(
+            "# HELP rojo_imfs_entry_count Number of entries in the in-memory filesystem.\n",
+        );
+        output.push_str("# TYPE rojo_imfs_entry_count gauge\n");
+        output.push_str(&format!(
+            "rojo_imfs_entry_count {}\n",
+            self.inner.imfs_entry_count.load(Ordering::SeqCst)
+        ));
+
+        output
+    }
+
+    pub fn handle_metrics(&self) -> Response<Body> {
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(self.render()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_histogram_observe_fills_every_bucket_at_or_above_the_value() {
+        let mut histogram = RouteHistogram::new();
+        histogram.observe(0.2);
+
+        for (bound, count) in HISTOGRAM_BUCKETS.iter().zip(histogram.bucket_counts.iter()) {
+            if *bound >= 0.2 {
+                assert_eq!(*count, 1, "bucket le={} should count a 0.2 sample", bound);
+            } else {
+                assert_eq!(
+                    *count, 0,
+                    "bucket le={} should not count a 0.2 sample",
+                    bound
+                );
+            }
+        }
+
+        assert_eq!(histogram.sum, 0.2);
+        assert_eq!(histogram.count, 1);
+    }
+
+    #[test]
+    fn render_emits_counters_gauges_and_histogram_buckets() {
+        let registry = Registry::new();
+        registry.observe_request("/show-instances", 200, 0.01);
+        registry.set_instance_count(42);
+        registry.set_imfs_entry_count(7);
+
+        let output = registry.render();
+
+        assert!(
+            output.contains("rojo_http_requests_total{route=\"/show-instances\",status=\"200\"} 1")
+        );
+        assert!(output.contains(
+            "rojo_http_request_duration_seconds_bucket{route=\"/show-instances\",le=\"0.025\"} 1"
+        ));
+        assert!(output.contains(
+            "rojo_http_request_duration_seconds_bucket{route=\"/show-instances\",le=\"+Inf\"} 1"
+        ));
+        assert!(output
+            .contains("rojo_http_request_duration_seconds_count{route=\"/show-instances\"} 1"));
+        assert!(output.contains("rojo_instance_count 42"));
+        assert!(output.contains("rojo_imfs_entry_count 7"));
+    }
+}
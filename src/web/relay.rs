@@ -0,0 +1,732 @@
+//! Implements a reverse relay that lets a `rojo serve` instance sitting
+//! behind NAT be reached through a public rendezvous host.
+//!
+//! A `rojo relay` process ([`RelayService`]) accepts inbound HTTP traffic on
+//! a public port and keeps one long-lived connection per registered server
+//! name ([`RelayState`]). When a client hits `https://host/<name>/api/...`,
+//! the relay strips the name prefix, hands the request to that server's
+//! channel, and waits for the matching response to come back over the same
+//! connection, the way a reverse HTTP relay multiplexes many private
+//! backends over one public port. A [`RelayConnection`] on the `rojo serve`
+//! side dials out to the relay, registers under a name, and pumps pending
+//! requests through the existing `UiService`/API router via
+//! [`connect_and_serve`]. This module adds no command-line subcommand or
+//! flags: nothing in this tree calls [`run_relay_server`] or
+//! [`connect_and_serve`].
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures::{
+    future,
+    sync::{mpsc, oneshot},
+    Future, Stream,
+};
+use hyper::{header, service::Service, Body, Method, Request, Response, Server, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use tokio::prelude::FutureExt;
+
+use crate::web::auth::constant_time_eq;
+
+/// How long the relay will wait for a registered server to answer a
+/// forwarded request before giving up.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a registered server's key stays valid without a successful
+/// [`RelayState::check_key`] renewing it. Refreshed every time the server
+/// posts a response back through `/_relay/respond/:name`, so a server
+/// that's still alive and answering never expires, but one that's gone
+/// silent (crashed, lost its connection, etc.) eventually does, freeing
+/// its name for someone else to register and preventing a stale key from
+/// being usable forever.
+const KEY_IDLE_TTL: Duration = Duration::from_secs(5 * 60);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A serialized HTTP request, forwarded from the relay to the `rojo serve`
+/// process registered under the request's server name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingRequest {
+    pub id: u64,
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// A serialized HTTP response, sent back from a `rojo serve` process to the
+/// relay in answer to a [`PendingRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingResponse {
+    pub id: u64,
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+struct RegisteredServer {
+    key: String,
+    outbound: mpsc::UnboundedSender<PendingRequest>,
+    waiting: Arc<DashMap<u64, oneshot::Sender<PendingResponse>>>,
+    expires_at: Instant,
+}
+
+/// Shared state held by a `rojo relay` process: every server currently
+/// registered, keyed by the name it dialed in with.
+#[derive(Clone)]
+pub struct RelayState {
+    servers: Arc<DashMap<String, RegisteredServer>>,
+}
+
+impl RelayState {
+    pub fn new() -> Self {
+        RelayState {
+            servers: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Registers a new server under `name`, returning the stream of
+    /// requests that should be forwarded to it. Rejects the registration if
+    /// `name` is already held by a connection with a different key and
+    /// that registration hasn't expired, so one stale key can't hijack
+    /// another project's routing slot. A name whose previous registration
+    /// expired (its server went quiet for [`KEY_IDLE_TTL`]) is free for
+    /// anyone to claim, same as one that was never registered.
+    pub fn register(
+        &self,
+        name: String,
+        key: String,
+    ) -> Result<mpsc::UnboundedReceiver<PendingRequest>, &'static str> {
+        if let Some(existing) = self.servers.get(&name) {
+            let still_alive = Instant::now() < existing.expires_at;
+            if still_alive && !constant_time_eq(existing.key.as_bytes(), key.as_bytes()) {
+                return Err("name is already registered with a different key");
+            }
+        }
+
+        let (outbound, inbound) = mpsc::unbounded();
+
+        self.servers.insert(
+            name,
+            RegisteredServer {
+                key,
+                outbound,
+                waiting: Arc::new(DashMap::new()),
+                expires_at: Instant::now() + KEY_IDLE_TTL,
+            },
+        );
+
+        Ok(inbound)
+    }
+
+    pub fn unregister(&self, name: &str) {
+        self.servers.remove(name);
+    }
+
+    /// Checks whether `key` matches, in constant time, the key a server
+    /// registered under `name` with, rejecting unknown or expired keys
+    /// before doing any work. On success, also renews the registration's
+    /// idle-expiry lease, since a successful check proves the server is
+    /// still alive and using its key.
+    pub fn check_key(&self, name: &str, key: &str) -> bool {
+        match self.servers.get_mut(name) {
+            Some(mut server) => {
+                if Instant::now() >= server.expires_at {
+                    return false;
+                }
+
+                if !constant_time_eq(server.key.as_bytes(), key.as_bytes()) {
+                    return false;
+                }
+
+                server.expires_at = Instant::now() + KEY_IDLE_TTL;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forwards an HTTP request to the server registered as `name`,
+    /// resolving with its response once the server answers or the request
+    /// times out.
+    pub fn forward(
+        &self,
+        name: &str,
+        method: String,
+        path: String,
+        body: Vec<u8>,
+    ) -> Box<dyn Future<Item = PendingResponse, Error = &'static str> + Send> {
+        let server = match self.servers.get(name) {
+            Some(server) => server,
+            None => return Box::new(future::err("no server registered under that name")),
+        };
+
+        let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        server.waiting.insert(id, tx);
+
+        let request = PendingRequest {
+            id,
+            method,
+            path,
+            body,
+        };
+
+        if server.outbound.unbounded_send(request).is_err() {
+            server.waiting.remove(&id);
+            return Box::new(future::err("server connection closed"));
+        }
+
+        let waiting = Arc::clone(&server.waiting);
+        let fut = rx
+            .map_err(|_| "server connection closed")
+            .timeout(RESPONSE_TIMEOUT)
+            .map_err(move |_| {
+                waiting.remove(&id);
+                "timed out waiting for server response"
+            });
+
+        Box::new(fut)
+    }
+
+    /// Delivers a response coming back over a registered server's
+    /// connection to whichever `forward` call is waiting on it.
+    pub fn complete(&self, name: &str, response: PendingResponse) {
+        if let Some(server) = self.servers.get(name) {
+            if let Some((_, tx)) = server.waiting.remove(&response.id) {
+                let _ = tx.send(response);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn register_allows_a_fresh_name() {
+        let state = RelayState::new();
+        assert!(state
+            .register("project".to_owned(), "key".to_owned())
+            .is_ok());
+    }
+
+    #[test]
+    fn register_allows_the_same_name_with_the_same_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+
+        assert!(state
+            .register("project".to_owned(), "key".to_owned())
+            .is_ok());
+    }
+
+    #[test]
+    fn register_rejects_the_same_name_with_a_different_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+
+        assert!(state
+            .register("project".to_owned(), "other-key".to_owned())
+            .is_err());
+    }
+
+    #[test]
+    fn register_allows_a_different_name_to_reuse_any_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+
+        assert!(state
+            .register("other-project".to_owned(), "key".to_owned())
+            .is_ok());
+    }
+
+    #[test]
+    fn check_key_accepts_the_registered_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+
+        assert!(state.check_key("project", "key"));
+    }
+
+    #[test]
+    fn check_key_rejects_a_wrong_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+
+        assert!(!state.check_key("project", "wrong-key"));
+    }
+
+    #[test]
+    fn check_key_rejects_an_unregistered_name() {
+        let state = RelayState::new();
+        assert!(!state.check_key("nonexistent", "key"));
+    }
+
+    #[test]
+    fn unregister_frees_the_name_for_a_different_key() {
+        let state = RelayState::new();
+        state
+            .register("project".to_owned(), "key".to_owned())
+            .unwrap();
+        state.unregister("project");
+
+        assert!(state
+            .register("project".to_owned(), "other-key".to_owned())
+            .is_ok());
+        assert!(!state.check_key("project", "key"));
+        assert!(state.check_key("project", "other-key"));
+    }
+
+    #[test]
+    fn split_name_splits_the_leading_path_segment() {
+        assert_eq!(
+            split_name("/foo/api/read/123"),
+            Some(("foo", "/api/read/123"))
+        );
+        assert_eq!(split_name("foo/bar"), Some(("foo", "/bar")));
+    }
+
+    #[test]
+    fn split_name_rejects_a_path_with_no_server_name() {
+        assert_eq!(split_name("/"), None);
+        assert_eq!(split_name(""), None);
+    }
+
+    #[test]
+    fn percent_encode_decode_round_trips_reserved_characters() {
+        let value = "a name/with & = % chars";
+        assert_eq!(percent_decode(&percent_encode(value)), value);
+    }
+
+    #[test]
+    fn query_param_decodes_percent_encoded_values() {
+        let query = format!("name={}&key=abc", percent_encode("my project"));
+        assert_eq!(query_param(&query, "name"), Some("my project".to_owned()));
+        assert_eq!(query_param(&query, "key"), Some("abc".to_owned()));
+        assert_eq!(query_param(&query, "missing"), None);
+    }
+}
+
+/// Splits a relay-facing path like `/foo/api/read/123` into the registered
+/// server name and the path that should be forwarded to it.
+pub fn split_name(path: &str) -> Option<(&str, &str)> {
+    let trimmed = path.trim_start_matches('/');
+    let slash = trimmed.find('/')?;
+    let (name, rest) = trimmed.split_at(slash);
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((name, rest))
+}
+
+/// Parses a `key=value&key=value` query string into pairs, without
+/// percent-decoding (every value we put in a query string here is
+/// percent-encoded with [`percent_encode`], and decoding undoes that
+/// symmetrically via [`percent_decode`]).
+fn parse_query(query: &str) -> Vec<(&str, &str)> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next()?;
+            let value = parts.next().unwrap_or("");
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<String> {
+    parse_query(query)
+        .into_iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// Percent-encodes `value` so it can be safely embedded as a single query
+/// string component: reserved characters like `&`, `=`, and `%` are escaped
+/// so a name or key containing them can't corrupt the query string or
+/// inject another field.
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    out
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The public-facing half of the relay: a `hyper::Service` that a `rojo
+/// relay` process serves on its public port. Handles registration
+/// (`GET /_relay/register`), responses coming back from registered servers
+/// (`POST /_relay/respond/:name`), and proxies everything else through
+/// [`RelayState::forward`] based on the leading path segment.
+pub struct RelayService {
+    state: RelayState,
+}
+
+impl RelayService {
+    pub fn new(state: RelayState) -> Self {
+        RelayService { state }
+    }
+
+    fn handle_register(&self, request: &Request<Body>) -> Response<Body> {
+        let query = request.uri().query().unwrap_or("");
+        let name = query_param(query, "name");
+        let key = query_param(query, "key");
+
+        let (name, key) = match (name, key) {
+            (Some(name), Some(key)) => (name, key),
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Missing name or key"))
+                    .unwrap()
+            }
+        };
+
+        match self.state.register(name, key) {
+            Ok(inbound) => {
+                let body = Body::wrap_stream(
+                    inbound
+                        .map(|pending| {
+                            let mut line = serde_json::to_vec(&pending).unwrap();
+                            line.push(b'\n');
+                            line
+                        })
+                        .map_err(|_| {
+                            std::io::Error::new(std::io::ErrorKind::Other, "relay channel closed")
+                        }),
+                );
+
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(body)
+                    .unwrap()
+            }
+            Err(err) => Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(Body::from(err))
+                .unwrap(),
+        }
+    }
+
+    fn handle_respond(
+        &self,
+        request: Request<Body>,
+        name: String,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let query = request.uri().query().unwrap_or("").to_owned();
+        let key = query_param(&query, "key");
+        let state = self.state.clone();
+
+        if !key.map(|key| state.check_key(&name, &key)).unwrap_or(false) {
+            return Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::from("Invalid or missing key"))
+                    .unwrap(),
+            ));
+        }
+
+        let fut = request.into_body().concat2().map(move |body| {
+            match serde_json::from_slice::<PendingResponse>(&body) {
+                Ok(response) => {
+                    state.complete(&name, response);
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                Err(_) => Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("Malformed response body"))
+                    .unwrap(),
+            }
+        });
+
+        Box::new(fut)
+    }
+
+    fn handle_proxy(
+        &self,
+        request: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let (name, mut rest) = match split_name(request.uri().path()) {
+            Some(parts) => (parts.0.to_owned(), parts.1.to_owned()),
+            None => {
+                return Box::new(future::ok(
+                    Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No server name in path"))
+                        .unwrap(),
+                ))
+            }
+        };
+
+        if let Some(query) = request.uri().query() {
+            rest.push('?');
+            rest.push_str(query);
+        }
+
+        let method = request.method().to_string();
+        let state = self.state.clone();
+
+        let fut = request.into_body().concat2().and_then(move |body| {
+            state
+                .forward(&name, method, rest, body.to_vec())
+                .then(|result| {
+                    let response = match result {
+                        Ok(pending) => Response::builder()
+                            .status(pending.status)
+                            .body(Body::from(pending.body))
+                            .unwrap(),
+                        Err(err) => Response::builder()
+                            .status(StatusCode::BAD_GATEWAY)
+                            .body(Body::from(err))
+                            .unwrap(),
+                    };
+
+                    Ok::<_, hyper::Error>(response)
+                })
+        });
+
+        Box::new(fut)
+    }
+}
+
+impl Service for RelayService {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+    fn call(&mut self, request: Request<Self::ReqBody>) -> Self::Future {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/_relay/register") => {
+                Box::new(future::ok(self.handle_register(&request)))
+            }
+            (&Method::POST, path) if path.starts_with("/_relay/respond/") => {
+                let name = percent_decode(path.trim_start_matches("/_relay/respond/"));
+                self.handle_respond(request, name)
+            }
+            _ => self.handle_proxy(request),
+        }
+    }
+}
+
+/// Runs a `rojo relay` process: serves [`RelayService`] on `addr` until the
+/// process is killed.
+pub fn run_relay_server(addr: std::net::SocketAddr) -> impl Future<Item = (), Error = ()> + Send {
+    let state = RelayState::new();
+
+    Server::bind(&addr)
+        .serve(move || {
+            let state = state.clone();
+            future::ok::<_, hyper::Error>(RelayService::new(state))
+        })
+        .map_err(|err| log::error!("Relay server error: {}", err))
+}
+
+/// Runs on the `rojo serve` side of a relay. Dials out to a relay host,
+/// registers under `name`, and feeds incoming [`PendingRequest`]s into a
+/// dispatcher that knows how to turn them into responses by calling through
+/// the existing `UiService`/API router.
+pub struct RelayConnection {
+    relay_url: String,
+    name: String,
+    key: String,
+}
+
+impl RelayConnection {
+    pub fn new(relay_url: String, name: String, key: String) -> Self {
+        RelayConnection {
+            relay_url,
+            name,
+            key,
+        }
+    }
+
+    /// Connects to the relay and forwards every request it sends us to
+    /// `dispatch`, posting the resulting response back. Runs until the
+    /// connection is lost, at which point the caller is expected to retry.
+    ///
+    /// The relay streams us one JSON-encoded `PendingRequest` per line, but
+    /// HTTP chunk boundaries aren't guaranteed to line up with line
+    /// boundaries, so incomplete lines are buffered across chunks instead of
+    /// being parsed (and silently dropped) chunk-by-chunk.
+    pub fn run<D>(&self, dispatch: D) -> Box<dyn Future<Item = (), Error = hyper::Error> + Send>
+    where
+        D: Fn(
+                PendingRequest,
+            ) -> Box<dyn Future<Item = PendingResponse, Error = hyper::Error> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
+        use hyper::{Body, Client, Request};
+
+        let client = Client::new();
+        let register_url = format!(
+            "{}/_relay/register?name={}&key={}",
+            self.relay_url,
+            percent_encode(&self.name),
+            percent_encode(&self.key)
+        );
+        let respond_url = format!(
+            "{}/_relay/respond/{}?key={}",
+            self.relay_url,
+            percent_encode(&self.name),
+            percent_encode(&self.key)
+        );
+        let dispatch = Arc::new(dispatch);
+        let mut buffer: Vec<u8> = Vec::new();
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(register_url)
+            .body(Body::empty())
+            .unwrap();
+
+        let fut = client.request(request).and_then(move |response| {
+            let client = client.clone();
+            let respond_url = respond_url.clone();
+            let dispatch = Arc::clone(&dispatch);
+
+            response.into_body().for_each(move |chunk| {
+                buffer.extend_from_slice(&chunk);
+
+                // Drain every complete line out of the buffer, leaving any
+                // trailing partial line in place for the next chunk.
+                while let Some(newline_index) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline_index).collect();
+                    let line = &line[..line.len() - 1];
+
+                    if line.is_empty() {
+                        continue;
+                    }
+
+                    match serde_json::from_slice::<PendingRequest>(line) {
+                        Ok(pending) => {
+                            let client = client.clone();
+                            let respond_url = respond_url.clone();
+
+                            let handled = dispatch(pending).and_then(move |response| {
+                                let body = serde_json::to_vec(&response).unwrap();
+                                let request = Request::builder()
+                                    .method(Method::POST)
+                                    .uri(respond_url)
+                                    .body(Body::from(body))
+                                    .unwrap();
+
+                                client.request(request).map(|_| ())
+                            });
+
+                            tokio::spawn(handled.map_err(|err| {
+                                log::error!("Error forwarding relay response: {}", err);
+                            }));
+                        }
+                        Err(err) => {
+                            log::error!("Dropping malformed relay request line: {}", err);
+                        }
+                    }
+                }
+
+                Ok(())
+            })
+        });
+
+        Box::new(fut)
+    }
+}
+
+/// Wires a [`RelayConnection`] up to an existing service, so every
+/// `PendingRequest` the relay forwards us is dispatched through the same
+/// `UiService`/API router that handles local traffic.
+pub fn connect_and_serve<S>(
+    connection: RelayConnection,
+    service: Arc<std::sync::Mutex<S>>,
+) -> Box<dyn Future<Item = (), Error = hyper::Error> + Send>
+where
+    S: Service<ReqBody = Body, ResBody = Body, Error = hyper::Error> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    connection.run(move |pending: PendingRequest| {
+        let uri: Uri = pending
+            .path
+            .parse()
+            .unwrap_or_else(|_| Uri::from_static("/"));
+        let method = pending.method.parse().unwrap_or(Method::GET);
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(pending.body))
+            .unwrap();
+
+        let service = Arc::clone(&service);
+        let id = pending.id;
+
+        let fut = service
+            .lock()
+            .unwrap()
+            .call(request)
+            .and_then(move |response| {
+                let status = response.status().as_u16();
+                response
+                    .into_body()
+                    .concat2()
+                    .map(move |body| PendingResponse {
+                        id,
+                        status,
+                        body: body.to_vec(),
+                    })
+            });
+
+        Box::new(fut)
+    })
+}
@@ -1,23 +1,145 @@
 //! Defines the HTTP-based UI. These endpoints generally return HTML and SVG.
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures::{future, Future};
+use futures::{future, Future, Stream};
 use hyper::{header, service::Service, Body, Method, Request, Response, StatusCode};
+use rbx_dom_weak::{RbxId, RbxTree};
 use ritz::{html, HtmlContent};
+use serde::Serialize;
 
 use crate::{
-    imfs::ImfsFetcher,
+    imfs::{Imfs, ImfsEntry, ImfsFetcher},
     serve_session::ServeSession,
     web::{
         assets,
+        auth::{self, AuthState},
+        caching, export,
         interface::{ErrorResponse, SERVER_VERSION},
+        metrics::Registry,
         util::json,
     },
 };
 
+/// JSON shape returned from `/show-imfs?format=json`: a nested view of
+/// one entry and its descendants.
+#[derive(Debug, Serialize)]
+struct ImfsEntryJson {
+    path: String,
+    is_directory: bool,
+    children: Vec<ImfsEntryJson>,
+}
+
+/// JSON shape returned from `/show-instances?format=json`.
+#[derive(Debug, Serialize)]
+struct InstanceJson {
+    id: String,
+    class_name: String,
+    name: String,
+    property_count: usize,
+    children: Vec<InstanceJson>,
+}
+
+/// Known route templates, one per exact path `route()` matches. Used to
+/// normalize a request path into a fixed metrics label before it's used as
+/// a `HashMap` key in [`Registry`], so a client can't grow
+/// `requests_total`/`request_duration_seconds` without bound just by
+/// hitting arbitrarily many distinct (and possibly unauthenticated, via
+/// junk 404 paths) URLs.
+const ROUTE_TEMPLATES: &[(&str, &str)] = &[
+    ("/", "/"),
+    ("/logo.png", "/logo.png"),
+    ("/icon.png", "/icon.png"),
+    ("/style.css", "/style.css"),
+    ("/show-instances", "/show-instances"),
+    ("/show-imfs", "/show-imfs"),
+    ("/metrics", "/metrics"),
+    ("/api/auth", "/api/auth"),
+    ("/api/export", "/api/export"),
+];
+
+/// Maps `path` to a fixed-cardinality metrics label: one of
+/// [`ROUTE_TEMPLATES`]'s templates for a known static route, `/api/read/:id`
+/// for any `/api/read/<id>`, or `other` for anything else (including 404s),
+/// so the number of distinct labels `Registry` ever sees is bounded by the
+/// number of routes this service actually has.
+fn route_label(path: &str) -> &'static str {
+    for (prefix, label) in ROUTE_TEMPLATES {
+        if path == *prefix {
+            return label;
+        }
+    }
+
+    if path.starts_with("/api/read/") {
+        return "/api/read/:id";
+    }
+
+    "other"
+}
+
+fn wants_json(query: Option<&str>) -> bool {
+    query
+        .map(|query| query.split('&').any(|pair| pair == "format=json"))
+        .unwrap_or(false)
+}
+
+/// Pulls `name`'s value out of a `key=value&key=value` query string.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next()?;
+        let value = parts.next()?;
+
+        if key == name {
+            Some(value.to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Resolves the instance `id` to the path its source files live at by
+/// walking up the tree from it to the root, collecting instance names along
+/// the way. Returns `None` if `id` isn't in `tree`.
+///
+/// This assumes the common Rojo convention that an instance's name matches
+/// the file or directory that produced it, so the path built by joining
+/// those names onto the project root is the subtree `/api/export` should
+/// zip up.
+fn subtree_path(tree: &RbxTree, root_path: &Path, id: RbxId) -> Option<PathBuf> {
+    let root_id = tree.get_root_id();
+    let mut names = Vec::new();
+    let mut current = id;
+
+    loop {
+        let instance = tree.get_instance(current)?;
+
+        if current == root_id {
+            break;
+        }
+
+        names.push(instance.name.clone());
+        current = instance.get_parent_id()?;
+    }
+
+    names.reverse();
+
+    let mut path = root_path.to_path_buf();
+    for name in names {
+        path.push(name);
+    }
+
+    Some(path)
+}
+
 pub struct UiService<F> {
     serve_session: Arc<ServeSession<F>>,
+    metrics: Registry,
+    auth: Arc<AuthState>,
 }
 
 impl<F: ImfsFetcher> Service for UiService<F> {
@@ -27,12 +149,62 @@ impl<F: ImfsFetcher> Service for UiService<F> {
     type Future = Box<dyn Future<Item = Response<Self::ReqBody>, Error = Self::Error> + Send>;
 
     fn call(&mut self, request: Request<Self::ReqBody>) -> Self::Future {
-        let response = match (request.method(), request.uri().path()) {
+        let route = route_label(request.uri().path());
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+
+        Box::new(self.route(request).map(move |response| {
+            let duration = start.elapsed();
+            let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+            metrics.observe_request(route, response.status().as_u16(), seconds);
+            response
+        }))
+    }
+}
+
+impl<F: ImfsFetcher> UiService<F> {
+    /// Routes `request` to a handler, after the `/api/auth` exchange and
+    /// behind the [`auth::authorized`] gate. Split out from `call` so it can
+    /// be wrapped in a latency/status observation there without every route
+    /// handler needing to know about `metrics`.
+    ///
+    /// This tree has no outer point that composes middleware around
+    /// `UiService` (there's no `serve.rs` that builds a service stack), so
+    /// the auth gate (here) and the latency/status recording (in `call`)
+    /// live inside `UiService` instead of wrapping it from the outside.
+    fn route(
+        &mut self,
+        request: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let path = request.uri().path().to_owned();
+
+        if request.method() == &Method::POST && path == "/api/auth" {
+            return self.handle_auth(request);
+        }
+
+        if !auth::authorized(&self.auth, &request, auth::TokenScope::Read) {
+            return json(
+                ErrorResponse::unauthorized("Missing or invalid authentication token"),
+                StatusCode::UNAUTHORIZED,
+            );
+        }
+
+        let query = request.uri().query().map(str::to_owned);
+
+        let response = match (request.method(), path.as_str()) {
             (&Method::GET, "/") => self.handle_home(),
-            (&Method::GET, "/logo.png") => self.handle_logo(),
-            (&Method::GET, "/icon.png") => self.handle_icon(),
-            (&Method::GET, "/show-instances") => self.handle_show_instances(),
-            (&Method::GET, "/show-imfs") => self.handle_show_imfs(),
+            (&Method::GET, "/logo.png") => self.handle_logo(&request),
+            (&Method::GET, "/icon.png") => self.handle_icon(&request),
+            (&Method::GET, "/style.css") => self.handle_css(&request),
+            (&Method::GET, "/show-instances") => {
+                self.handle_show_instances(wants_json(query.as_deref()))
+            }
+            (&Method::GET, "/show-imfs") => self.handle_show_imfs(wants_json(query.as_deref())),
+            (&Method::GET, "/metrics") => self.handle_metrics(),
+            (&Method::GET, "/api/export") => return self.handle_export(query.as_deref()),
+            (&Method::GET, path) if path.starts_with("/api/read/") => {
+                return self.handle_read(&request, &path["/api/read/".len()..])
+            }
             (_method, path) => {
                 return json(
                     ErrorResponse::not_found(format!("Route not found: {}", path)),
@@ -43,25 +215,146 @@ impl<F: ImfsFetcher> Service for UiService<F> {
 
         Box::new(future::ok(response))
     }
-}
 
-impl<F: ImfsFetcher> UiService<F> {
-    pub fn new(serve_session: Arc<ServeSession<F>>) -> Self {
-        UiService { serve_session }
+    pub fn new(
+        serve_session: Arc<ServeSession<F>>,
+        metrics: Registry,
+        auth: Arc<AuthState>,
+    ) -> Self {
+        UiService {
+            serve_session,
+            metrics,
+            auth,
+        }
     }
 
-    fn handle_logo(&self) -> Response<Body> {
-        Response::builder()
-            .header(header::CONTENT_TYPE, "image/png")
-            .body(Body::from(assets::logo()))
-            .unwrap()
+    fn handle_auth(
+        &self,
+        request: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let auth = Arc::clone(&self.auth);
+
+        let fut =
+            request.into_body().concat2().and_then(move |body| {
+                match serde_json::from_slice::<auth::AuthRequest>(&body) {
+                    Ok(parsed) => auth::handle_auth_request(&auth, &parsed.secret, parsed.scope),
+                    Err(_) => json(
+                        ErrorResponse::bad_request("Malformed auth request body"),
+                        StatusCode::BAD_REQUEST,
+                    ),
+                }
+            });
+
+        Box::new(fut)
     }
 
-    fn handle_icon(&self) -> Response<Body> {
-        Response::builder()
-            .header(header::CONTENT_TYPE, "image/png")
-            .body(Body::from(assets::icon()))
-            .unwrap()
+    fn handle_metrics(&self) -> Response<Body> {
+        self.metrics.handle_metrics()
+    }
+
+    /// Handles `GET /api/export`, zipping up the whole project, or just the
+    /// subtree rooted at `?id=<RbxId>` when one is given.
+    fn handle_export(
+        &self,
+        query: Option<&str>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let mut imfs = self.serve_session.imfs();
+        let root_path = self.serve_session.root_path().to_path_buf();
+        let project_name = self
+            .serve_session
+            .project_name()
+            .unwrap_or("project")
+            .to_owned();
+
+        let export_path = match query.and_then(|query| query_param(query, "id")) {
+            Some(id_str) => match id_str.parse::<RbxId>() {
+                Ok(id) => {
+                    let tree = self.serve_session.tree();
+
+                    match subtree_path(&tree, &root_path, id) {
+                        Some(path) => path,
+                        None => {
+                            return json(
+                                ErrorResponse::not_found(format!("No instance with id {}", id_str)),
+                                StatusCode::NOT_FOUND,
+                            )
+                        }
+                    }
+                }
+                Err(_) => {
+                    return json(
+                        ErrorResponse::bad_request(format!("Invalid instance id: {}", id_str)),
+                        StatusCode::BAD_REQUEST,
+                    )
+                }
+            },
+            None => root_path,
+        };
+
+        let entry = match imfs.get(&export_path) {
+            Ok(entry) => entry,
+            Err(err) => {
+                return json(
+                    ErrorResponse::internal_error(format!("Could not read project files: {}", err)),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        };
+
+        export::handle_export(&mut imfs, &entry, &project_name)
+    }
+
+    /// Handles `GET /api/read/:id`, returning the instance `id` and its
+    /// descendants as JSON. Linked to from the "read" link next to every
+    /// entry in `/show-instances`. Conditional on `If-None-Match` via
+    /// [`caching::revalidated_response`], since a client polling a single
+    /// instance for changes shouldn't have to pay for the body every time
+    /// nothing changed.
+    fn handle_read(
+        &self,
+        request: &Request<Body>,
+        id_str: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let id = match id_str.parse::<RbxId>() {
+            Ok(id) => id,
+            Err(_) => {
+                return json(
+                    ErrorResponse::bad_request(format!("Invalid instance id: {}", id_str)),
+                    StatusCode::BAD_REQUEST,
+                )
+            }
+        };
+
+        let tree = self.serve_session.tree();
+
+        if tree.get_instance(id).is_none() {
+            return json(
+                ErrorResponse::not_found(format!("No instance with id {}", id_str)),
+                StatusCode::NOT_FOUND,
+            );
+        }
+
+        let body = serde_json::to_vec(&Self::instance_to_json(&tree, id)).unwrap();
+        let etag = caching::etag_for_bytes(&body);
+
+        Box::new(future::ok(caching::revalidated_response(
+            request,
+            "application/json",
+            &etag,
+            body,
+        )))
+    }
+
+    fn handle_logo(&self, request: &Request<Body>) -> Response<Body> {
+        caching::immutable_asset_response(request, "image/png", assets::logo())
+    }
+
+    fn handle_icon(&self, request: &Request<Body>) -> Response<Body> {
+        caching::immutable_asset_response(request, "image/png", assets::icon())
+    }
+
+    fn handle_css(&self, request: &Request<Body>) -> Response<Body> {
+        caching::immutable_asset_response(request, "text/css", assets::css().as_bytes())
     }
 
     fn handle_home(&self) -> Response<Body> {
@@ -70,6 +363,7 @@ impl<F: ImfsFetcher> UiService<F> {
                 { Self::button("Rojo Documentation", "https://rojo.space/docs") }
                 { Self::button("View in-memory filesystem state", "/show-imfs") }
                 { Self::button("View instance tree state", "/show-instances") }
+                { Self::button("Export project as a ZIP", "/api/export") }
             </div>
         });
 
@@ -79,20 +373,214 @@ impl<F: ImfsFetcher> UiService<F> {
             .unwrap()
     }
 
-    fn handle_show_instances(&self) -> Response<Body> {
+    fn handle_show_instances(&self, as_json: bool) -> Response<Body> {
+        let tree = self.serve_session.tree();
+
+        self.metrics
+            .set_instance_count(Self::count_instances(&tree, tree.get_root_id()));
+
+        if as_json {
+            let root_id = tree.get_root_id();
+            let json = Self::instance_to_json(&tree, root_id);
+
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&json).unwrap()))
+                .unwrap();
+        }
+
+        let root_id = tree.get_root_id();
+        let page = self.normal_page(html! {
+            <div class="instance-tree">
+                { Self::instance_to_html(&tree, root_id) }
+            </div>
+        });
+
         Response::builder()
-            .header(header::CONTENT_TYPE, "text/plain")
-            .body(Body::from("TODO: /show-instances"))
+            .header(header::CONTENT_TYPE, "text/html")
+            .body(Body::from(format!("<!DOCTYPE html>{}", page)))
             .unwrap()
     }
 
-    fn handle_show_imfs(&self) -> Response<Body> {
+    /// Counts `id` and every instance below it, for the `rojo_instance_count`
+    /// gauge.
+    fn count_instances(tree: &rbx_dom_weak::RbxTree, id: RbxId) -> u64 {
+        let instance = tree
+            .get_instance(id)
+            .expect("instance ids in the tree always resolve");
+
+        1 + instance
+            .get_children_ids()
+            .iter()
+            .map(|&child_id| Self::count_instances(tree, child_id))
+            .sum::<u64>()
+    }
+
+    fn instance_to_json(tree: &rbx_dom_weak::RbxTree, id: RbxId) -> InstanceJson {
+        let instance = tree
+            .get_instance(id)
+            .expect("instance ids in the tree always resolve");
+
+        InstanceJson {
+            id: id.to_string(),
+            class_name: instance.class_name.clone(),
+            name: instance.name.clone(),
+            property_count: instance.properties.len(),
+            children: instance
+                .get_children_ids()
+                .iter()
+                .map(|&child_id| Self::instance_to_json(tree, child_id))
+                .collect(),
+        }
+    }
+
+    fn instance_to_html(tree: &rbx_dom_weak::RbxTree, id: RbxId) -> HtmlContent<'static> {
+        let instance = tree
+            .get_instance(id)
+            .expect("instance ids in the tree always resolve");
+
+        let children: Vec<_> = instance
+            .get_children_ids()
+            .iter()
+            .map(|&child_id| Self::instance_to_html(tree, child_id))
+            .collect();
+
+        let read_href = format!("/api/read/{}", id);
+
+        html! {
+            <details class="tree-entry" open="true">
+                <summary>
+                    <span class="tree-entry-name">{ instance.name.clone() }</span>
+                    " "
+                    <span class="tree-entry-class">"(" { instance.class_name.clone() } ")"</span>
+                    " "
+                    <a class="tree-entry-link" href={ read_href }>"read"</a>
+                    " "
+                    { Self::stat_item("properties", instance.properties.len().to_string()) }
+                </summary>
+                <div class="tree-entry-children">
+                    { children }
+                </div>
+            </details>
+        }
+    }
+
+    fn handle_show_imfs(&self, as_json: bool) -> Response<Body> {
+        let mut imfs = self.serve_session.imfs();
+        let root_path = self.serve_session.root_path().to_path_buf();
+
+        let entry = match imfs.get(&root_path) {
+            Ok(entry) => entry,
+            Err(err) => {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from(format!("Could not read project path: {}", err)))
+                    .unwrap();
+            }
+        };
+
+        self.metrics
+            .set_imfs_entry_count(Self::count_imfs_entries(&mut imfs, &entry));
+
+        if as_json {
+            let json = Self::imfs_entry_to_json(&mut imfs, &entry);
+
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&json).unwrap()))
+                .unwrap();
+        }
+
+        let page = self.normal_page(html! {
+            <div class="imfs-tree">
+                { Self::imfs_entry_to_html(&mut imfs, &entry) }
+            </div>
+        });
+
         Response::builder()
-            .header(header::CONTENT_TYPE, "text/plain")
-            .body(Body::from("TODO: /show-imfs"))
+            .header(header::CONTENT_TYPE, "text/html")
+            .body(Body::from(format!("<!DOCTYPE html>{}", page)))
             .unwrap()
     }
 
+    /// Counts `entry` and every entry below it, for the
+    /// `rojo_imfs_entry_count` gauge.
+    fn count_imfs_entries<Fetcher: ImfsFetcher>(
+        imfs: &mut Imfs<Fetcher>,
+        entry: &ImfsEntry,
+    ) -> u64 {
+        if entry.is_directory() {
+            1 + entry
+                .children(imfs)
+                .unwrap_or_default()
+                .iter()
+                .map(|child| Self::count_imfs_entries(imfs, child))
+                .sum::<u64>()
+        } else {
+            1
+        }
+    }
+
+    fn imfs_entry_to_json<Fetcher: ImfsFetcher>(
+        imfs: &mut Imfs<Fetcher>,
+        entry: &ImfsEntry,
+    ) -> ImfsEntryJson {
+        let children = if entry.is_directory() {
+            entry
+                .children(imfs)
+                .unwrap_or_default()
+                .iter()
+                .map(|child| Self::imfs_entry_to_json(imfs, child))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        ImfsEntryJson {
+            path: entry.path().to_string_lossy().into_owned(),
+            is_directory: entry.is_directory(),
+            children,
+        }
+    }
+
+    fn imfs_entry_to_html<Fetcher: ImfsFetcher>(
+        imfs: &mut Imfs<Fetcher>,
+        entry: &ImfsEntry,
+    ) -> HtmlContent<'static> {
+        let name = entry
+            .path()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| entry.path().to_string_lossy().into_owned());
+
+        let kind = if entry.is_directory() { "dir" } else { "file" };
+
+        let children: Vec<_> = if entry.is_directory() {
+            entry
+                .children(imfs)
+                .unwrap_or_default()
+                .iter()
+                .map(|child| Self::imfs_entry_to_html(imfs, child))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        html! {
+            <details class="tree-entry" open="true">
+                <summary>
+                    <span class="tree-entry-name">{ name }</span>
+                    " "
+                    <span class="tree-entry-class">"(" { kind } ")"</span>
+                </summary>
+                <div class="tree-entry-children">
+                    { children }
+                </div>
+            </details>
+        }
+    }
+
     fn stat_item<S: Into<String>>(name: &str, value: S) -> HtmlContent<'_> {
         html! {
             <span class="stat">
@@ -145,9 +633,7 @@ impl<F: ImfsFetcher> UiService<F> {
                     <title>"Rojo Live Server"</title>
                     <link rel="icon" type="image/png" sizes="32x32" href="/icon.png" />
                     <meta name="viewport" content="width=device-width, initial-scale=1, minimum-scale=1, maximum-scale=1" />
-                    <style>
-                        { ritz::UnescapedText::new(assets::css()) }
-                    </style>
+                    <link rel="stylesheet" type="text/css" href="/style.css" />
                 </head>
 
                 <body>
@@ -156,4 +642,55 @@ impl<F: ImfsFetcher> UiService<F> {
             </html>
         }
     }
-}
\ No newline at end of file
+}
+
+// The tree-rendering/JSON handlers above this point (`instance_to_json`,
+// `instance_to_html`, `count_instances`, the `imfs_entry_*` equivalents,
+// `stat_item`, `button`, `normal_page`, `page`) aren't covered here: they're
+// all methods on `UiService<F>`/take `&RbxTree`, and this snapshot has no
+// `imfs.rs`, `serve_session.rs`, or `rbx_dom_weak` source anywhere in the
+// tree, so there's no concrete `ImfsFetcher`/`RbxTree` to construct a real
+// instance from in a test. The free functions that don't need one
+// (`route_label`, `query_param`, `wants_json`) are tested below.
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_label_matches_known_static_routes() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/show-instances"), "/show-instances");
+        assert_eq!(route_label("/metrics"), "/metrics");
+    }
+
+    #[test]
+    fn route_label_collapses_dynamic_read_ids() {
+        assert_eq!(route_label("/api/read/deadbeef"), "/api/read/:id");
+        assert_eq!(route_label("/api/read/anything-at-all"), "/api/read/:id");
+    }
+
+    #[test]
+    fn route_label_collapses_unknown_paths_instead_of_growing_without_bound() {
+        assert_eq!(route_label("/does-not-exist"), "other");
+        assert_eq!(route_label("/does/not/exist/either"), "other");
+    }
+
+    #[test]
+    fn query_param_finds_the_requested_key() {
+        assert_eq!(query_param("a=1&id=abc&b=2", "id"), Some("abc".to_owned()));
+    }
+
+    #[test]
+    fn query_param_returns_none_when_missing() {
+        assert_eq!(query_param("a=1&b=2", "id"), None);
+    }
+
+    #[test]
+    fn wants_json_checks_for_format_json() {
+        assert!(wants_json(Some("format=json")));
+        assert!(wants_json(Some("a=1&format=json")));
+        assert!(!wants_json(Some("format=html")));
+        assert!(!wants_json(None));
+    }
+}